@@ -1,10 +1,12 @@
 use std::{fs, io, env};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::DirEntry;
+use std::io::Write;
 use std::path::Path;
 
-use image::{ImageBuffer, RgbaImage, RgbImage};
+use image::{AnimationDecoder, DynamicImage, ImageBuffer, ImageFormat, RgbaImage, RgbImage};
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
 use image::error::DecodingError;
 use image::imageops::FilterType;
 use image::io::Reader as ImageReader;
@@ -15,8 +17,6 @@ use vecmath::{vec3_add, vec3_scale};
 
 use std::process::exit;
 
-use lazy_static::lazy_static;
-
 type RGBColor3f = [f32; 3];
 type RGBColor3b = [u8; 3];
 
@@ -55,7 +55,9 @@ const C64_LIGHT_BLUE: RGBColor3b = [0x88, 0x7e, 0xcb];
 const C64_LIGHT_GREY: RGBColor3b = [0xad, 0xad, 0xad];
 
 
-const C64_PALETTE_ALL_3B: [RGBColor3b; 16] = [
+// The palette this tool shipped with from the start (close to the Pepto
+// measurements). Kept as the default so existing invocations are unaffected.
+const C64_PALETTE_PEPTO: [RGBColor3b; 16] = [
     C64_BLACK,          // black
     C64_WHITE,    // white
     C64_RED,        // red
@@ -74,11 +76,21 @@ const C64_PALETTE_ALL_3B: [RGBColor3b; 16] = [
     C64_LIGHT_GREY,    // light grey / grey 3
 ];
 
-lazy_static! { static ref C64_PALETTE_ALL_3F: Vec<(RGBColor3f, RGBColor3b)> = C64_PALETTE_ALL_3B.iter()
-        .cloned()
-        .map(|i| (rgb_to_vec3f(i), i))
-        .collect();
-}
+// Approximate Colodore values (colodore.com), a touch more saturated than Pepto.
+const C64_PALETTE_COLODORE: [RGBColor3b; 16] = [
+    [0x00, 0x00, 0x00], [0xff, 0xff, 0xff], [0x81, 0x33, 0x38], [0x75, 0xce, 0xc8],
+    [0x8e, 0x3c, 0x97], [0x56, 0xac, 0x4d], [0x2e, 0x2c, 0x9b], [0xed, 0xf1, 0x71],
+    [0x8e, 0x50, 0x29], [0x55, 0x38, 0x00], [0xc4, 0x6c, 0x71], [0x4a, 0x4a, 0x4a],
+    [0x7b, 0x7b, 0x7b], [0xa9, 0xff, 0x9f], [0x70, 0x6d, 0xeb], [0xb2, 0xb2, 0xb2],
+];
+
+// Approximate VICE "C64S" palette values.
+const C64_PALETTE_VICE: [RGBColor3b; 16] = [
+    [0x00, 0x00, 0x00], [0xfc, 0xfc, 0xfc], [0xa8, 0x43, 0x3d], [0x65, 0xd0, 0xd5],
+    [0xa9, 0x4b, 0xb7], [0x54, 0xb3, 0x46], [0x41, 0x36, 0xae], [0xe6, 0xea, 0x51],
+    [0xae, 0x6a, 0x29], [0x6a, 0x53, 0x00], [0xd5, 0x8e, 0x87], [0x5b, 0x5b, 0x5b],
+    [0x8b, 0x8b, 0x8b], [0xae, 0xea, 0x9d], [0x8d, 0x7a, 0xec], [0xb5, 0xb5, 0xb5],
+];
 
 fn rgb_to_vec3f(rgb: RGBColor3b) -> RGBColor3f {
     [rgb[0] as f32 / 255.0, rgb[1] as f32 / 255.0, rgb[2] as f32 / 255.0]
@@ -90,17 +102,206 @@ fn rgb_to_lab3f(rgb: RGBColor3f) -> RGBColor3f {
     return [lab.a, lab.b, lab.l];
 }
 
+/// A fixed 16-color C64 palette, both as raw bytes (for file output/lookup)
+/// and pre-converted to float RGB (for the dithering error table).
+struct Palette {
+    entries: Vec<(RGBColor3f, RGBColor3b)>,
+}
+
+impl Palette {
+    fn from_colors(colors: &[RGBColor3b; 16]) -> Palette {
+        let entries = colors.iter().cloned().map(|c| (rgb_to_vec3f(c), c)).collect();
+        Palette { entries }
+    }
+
+    /// Resolve a built-in palette by name (case-insensitive).
+    fn named(name: &str) -> Option<Palette> {
+        match name.to_lowercase().as_str() {
+            "pepto" => Some(Palette::from_colors(&C64_PALETTE_PEPTO)),
+            "colodore" => Some(Palette::from_colors(&C64_PALETTE_COLODORE)),
+            "vice" => Some(Palette::from_colors(&C64_PALETTE_VICE)),
+            _ => None,
+        }
+    }
+
+    /// Parse 16 whitespace/comma separated `RRGGBB` (optionally `#RRGGBB`)
+    /// hex entries into a palette.
+    fn from_hex(text: &str) -> Result<Palette, Box<dyn Error>> {
+        let mut colors: Vec<RGBColor3b> = Vec::new();
+        for entry in text.split(|c: char| c.is_whitespace() || c == ',') {
+            let entry = entry.trim().trim_start_matches('#');
+            if entry.is_empty() {
+                continue;
+            }
+            if entry.len() != 6 {
+                return Err(Box::new(SimpleError::new(format!("palette entry '{}' is not 6 hex digits", entry))));
+            }
+            let r = u8::from_str_radix(&entry[0..2], 16)?;
+            let g = u8::from_str_radix(&entry[2..4], 16)?;
+            let b = u8::from_str_radix(&entry[4..6], 16)?;
+            colors.push([r, g, b]);
+        }
+
+        if colors.len() != 16 {
+            return Err(Box::new(SimpleError::new(format!("palette needs exactly 16 colors, got {}", colors.len()))));
+        }
+
+        let mut fixed = [C64_BLACK; 16];
+        fixed.copy_from_slice(&colors);
+        Ok(Palette::from_colors(&fixed))
+    }
+
+    /// Load a named built-in palette, falling back to treating `value` as a
+    /// path to a file of hex-encoded colors.
+    fn load(value: &str) -> Result<Palette, Box<dyn Error>> {
+        if let Some(palette) = Palette::named(value) {
+            return Ok(palette);
+        }
+        let text = fs::read_to_string(value)?;
+        Palette::from_hex(&text)
+    }
+}
+
+/// Color difference metric used to rank palette candidates in `rgbv_error_table`.
+#[derive(Clone, Copy)]
+enum DeltaE {
+    Euclidean,
+    Cie94,
+    Ciede2000,
+}
 
-fn rgbv_error_table(rgb: [f32; 3], error: [f32; 3]) -> Vec<(u32, RGBColor3f, [u8; 3])> {
+fn delta_e_named(name: &str) -> Option<DeltaE> {
+    match name.to_lowercase().as_str() {
+        "euclidean" => Some(DeltaE::Euclidean),
+        "cie94" => Some(DeltaE::Cie94),
+        "ciede2000" | "cie2000" => Some(DeltaE::Ciede2000),
+        _ => None,
+    }
+}
+
+/// `rgb_to_lab3f` returns `[a, b, l]`, not `[l, a, b]`; pull the components
+/// back out in the order the textbook formulas expect.
+fn lab_components(lab: RGBColor3f) -> (f32, f32, f32) {
+    (lab[2], lab[0], lab[1])
+}
+
+fn hue_degrees(a: f32, b: f32) -> f32 {
+    if a == 0.0 && b == 0.0 {
+        0.0
+    } else {
+        let deg = b.atan2(a).to_degrees();
+        if deg < 0.0 { deg + 360.0 } else { deg }
+    }
+}
+
+fn delta_e_cie94(lab1: RGBColor3f, lab2: RGBColor3f) -> f32 {
+    let (l1, a1, b1) = lab_components(lab1);
+    let (l2, a2, b2) = lab_components(lab2);
+
+    let c1 = (a1 * a1 + b1 * b1).sqrt();
+    let c2 = (a2 * a2 + b2 * b2).sqrt();
+
+    let delta_l = l1 - l2;
+    let delta_c = c1 - c2;
+    let delta_h = ((a1 - a2).powi(2) + (b1 - b2).powi(2) - delta_c.powi(2)).max(0.0).sqrt();
+
+    let s_l = 1.0;
+    let s_c = 1.0 + 0.045 * c1;
+    let s_h = 1.0 + 0.015 * c1;
+
+    ((delta_l / s_l).powi(2) + (delta_c / s_c).powi(2) + (delta_h / s_h).powi(2)).sqrt()
+}
+
+/// CIEDE2000 color difference between two Lab colors.
+fn delta_e_ciede2000(lab1: RGBColor3f, lab2: RGBColor3f) -> f32 {
+    let (l1, a1, b1) = lab_components(lab1);
+    let (l2, a2, b2) = lab_components(lab2);
+
+    let c1_ab = (a1 * a1 + b1 * b1).sqrt();
+    let c2_ab = (a2 * a2 + b2 * b2).sqrt();
+    let c_bar = (c1_ab + c2_ab) / 2.0;
+
+    let c_bar7 = c_bar.powi(7);
+    let g = 0.5 * (1.0 - (c_bar7 / (c_bar7 + 25f32.powi(7))).sqrt());
+
+    let a1p = (1.0 + g) * a1;
+    let a2p = (1.0 + g) * a2;
+
+    let c1p = (a1p * a1p + b1 * b1).sqrt();
+    let c2p = (a2p * a2p + b2 * b2).sqrt();
+
+    let h1p = hue_degrees(a1p, b1);
+    let h2p = hue_degrees(a2p, b2);
+
+    let delta_l = l2 - l1;
+    let delta_c = c2p - c1p;
+
+    let delta_h_deg = if c1p * c2p == 0.0 {
+        0.0
+    } else {
+        let mut dh = h2p - h1p;
+        if dh > 180.0 {
+            dh -= 360.0;
+        } else if dh < -180.0 {
+            dh += 360.0;
+        }
+        dh
+    };
+    let delta_h = 2.0 * (c1p * c2p).sqrt() * (delta_h_deg.to_radians() / 2.0).sin();
 
-    let mut errors: Vec<(u32, RGBColor3f, RGBColor3b)> = C64_PALETTE_ALL_3F.iter().map(|(rgb_3f, rgb_3b)| {
+    let l_bar = (l1 + l2) / 2.0;
+    let c_bar_p = (c1p + c2p) / 2.0;
+
+    let h_bar_p = if c1p * c2p == 0.0 {
+        h1p + h2p
+    } else if (h1p - h2p).abs() <= 180.0 {
+        (h1p + h2p) / 2.0
+    } else if h1p + h2p < 360.0 {
+        (h1p + h2p + 360.0) / 2.0
+    } else {
+        (h1p + h2p - 360.0) / 2.0
+    };
+
+    let t = 1.0
+        - 0.17 * (h_bar_p - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_bar_p).to_radians().cos()
+        + 0.32 * (3.0 * h_bar_p + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_bar_p - 63.0).to_radians().cos();
+
+    let s_l = 1.0 + (0.015 * (l_bar - 50.0).powi(2)) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar_p;
+    let s_h = 1.0 + 0.015 * c_bar_p * t;
+
+    let delta_theta = 30.0 * (-((h_bar_p - 275.0) / 25.0).powi(2)).exp();
+    let c_bar_p7 = c_bar_p.powi(7);
+    let r_c = 2.0 * (c_bar_p7 / (c_bar_p7 + 25f32.powi(7))).sqrt();
+    let r_t = -(2.0 * delta_theta.to_radians()).sin() * r_c;
+
+    let term_l = delta_l / s_l;
+    let term_c = delta_c / s_c;
+    let term_h = delta_h / s_h;
+
+    (term_l * term_l + term_c * term_c + term_h * term_h + r_t * term_c * term_h).sqrt()
+}
+
+fn delta_e(mode: DeltaE, lab1: RGBColor3f, lab2: RGBColor3f) -> f32 {
+    match mode {
+        DeltaE::Euclidean => vecmath::vec3_len(vecmath::vec3_sub(lab1, lab2)),
+        DeltaE::Cie94 => delta_e_cie94(lab1, lab2),
+        DeltaE::Ciede2000 => delta_e_ciede2000(lab1, lab2),
+    }
+}
+
+fn rgbv_error_table(palette: &Palette, mode: DeltaE, rgb: [f32; 3], error: [f32; 3]) -> Vec<(u32, RGBColor3f, [u8; 3])> {
+
+    let mut errors: Vec<(u32, RGBColor3f, RGBColor3b)> = palette.entries.iter().map(|(rgb_3f, rgb_3b)| {
         let mut a1 = vec3_add(rgb_to_lab3f(rgb), vec3_scale(error, 0.7));
         a1[0] = clamp(a1[0], 0.0, 100.0);
         a1[1] = clamp(a1[1], -128.0, 127.0);
         a1[2] = clamp(a1[2], -128.0, 127.0);
 
         let sub = vecmath::vec3_sub(a1, rgb_to_lab3f(*rgb_3f));
-        let d = vecmath::vec3_len(sub);
+        let d = delta_e(mode, a1, rgb_to_lab3f(*rgb_3f));
 
         ((d * 255.0).abs() as u32, sub, *rgb_3b)
     })
@@ -124,34 +325,100 @@ pub fn clamp(s: f32, min: f32, max: f32) -> f32 {
     x
 }
 
-fn c64_dither(image: &mut RgbImage) {
+/// An error-diffusion kernel: (dx, dy, weight) taps relative to the pixel
+/// just quantized, plus the divisor the weights are expressed over.
+struct DiffusionKernel {
+    name: &'static str,
+    taps: &'static [(i32, i32, f32)],
+    divisor: f32,
+}
 
-    let mut lum_acc_vec_error: RGBColor3f = [0.0, 0.0, 0.0];
+const KERNEL_FLOYD_STEINBERG: DiffusionKernel = DiffusionKernel {
+    name: "floyd-steinberg",
+    taps: &[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)],
+    divisor: 16.0,
+};
 
-    image.enumerate_pixels_mut().for_each(|(x, y, p)| {
+// Atkinson only distributes 6/8 of the error, leaving the rest behind on purpose.
+const KERNEL_ATKINSON: DiffusionKernel = DiffusionKernel {
+    name: "atkinson",
+    taps: &[(1, 0, 1.0), (2, 0, 1.0), (-1, 1, 1.0), (0, 1, 1.0), (1, 1, 1.0), (0, 2, 1.0)],
+    divisor: 8.0,
+};
 
-        // c64 multi color mode double pixel in x directions
-        // XXX SKerkewitz: we should still track the error
-        if x % 2 != 0 {
-            return
-        }
+const KERNEL_JARVIS_JUDICE_NINKE: DiffusionKernel = DiffusionKernel {
+    name: "jarvis-judice-ninke",
+    taps: &[
+        (1, 0, 7.0), (2, 0, 5.0),
+        (-2, 1, 3.0), (-1, 1, 5.0), (0, 1, 7.0), (1, 1, 5.0), (2, 1, 3.0),
+        (-2, 2, 1.0), (-1, 2, 3.0), (0, 2, 5.0), (1, 2, 3.0), (2, 2, 1.0),
+    ],
+    divisor: 48.0,
+};
+
+const KERNEL_SIERRA: DiffusionKernel = DiffusionKernel {
+    name: "sierra",
+    taps: &[
+        (1, 0, 5.0), (2, 0, 3.0),
+        (-2, 1, 2.0), (-1, 1, 4.0), (0, 1, 5.0), (1, 1, 4.0), (2, 1, 2.0),
+        (-1, 2, 2.0), (0, 2, 3.0), (1, 2, 2.0),
+    ],
+    divisor: 32.0,
+};
+
+fn diffusion_kernel_named(name: &str) -> Option<&'static DiffusionKernel> {
+    match name.to_lowercase().as_str() {
+        "floyd-steinberg" | "floyd_steinberg" | "fs" => Some(&KERNEL_FLOYD_STEINBERG),
+        "atkinson" => Some(&KERNEL_ATKINSON),
+        "jarvis-judice-ninke" | "jjn" => Some(&KERNEL_JARVIS_JUDICE_NINKE),
+        "sierra" => Some(&KERNEL_SIERRA),
+        _ => None,
+    }
+}
 
-        // reset the error for each line
-        if x == 0 {
-            lum_acc_vec_error = [0.0, 0.0, 0.0];
+/// Spread a pixel's quantization `error` into its not-yet-visited neighbors
+/// per `kernel`, accumulating into the per-pixel `errors` buffer. `dx` is
+/// mirrored when scanning right-to-left (serpentine), and doubled because
+/// only every second column is an independently ditherable multicolor pixel.
+fn diffuse_error(errors: &mut Vec<RGBColor3f>, width: u32, height: u32, x: u32, y: u32, error: RGBColor3f, kernel: &DiffusionKernel, left_to_right: bool) {
+    for (dx, dy, weight) in kernel.taps {
+        let dx = if left_to_right { *dx } else { -*dx };
+        let nx = x as i32 + dx * 2;
+        let ny = y as i32 + dy;
+        if nx < 0 || ny < 0 || nx as u32 >= width || ny as u32 >= height {
+            continue;
         }
 
-        // if (y / 1) % 2 == 0 {
-        //     lum_acc_vec_error = [0.0, 0.0, 0.0];
-        // }
+        let index = (ny as u32 * width + nx as u32) as usize;
+        errors[index] = vec3_add(errors[index], vec3_scale(error, weight / kernel.divisor));
+    }
+}
+
+fn c64_dither(palette: &Palette, delta_e_mode: DeltaE, image: &mut RgbImage, kernel: &DiffusionKernel, serpentine: bool) {
+    let width = image.width();
+    let height = image.height();
+    let mut errors: Vec<RGBColor3f> = vec![[0.0, 0.0, 0.0]; (width * height) as usize];
 
-        let rgbv_error = rgbv_error_table(rgb_to_vec3f(p.0), lum_acc_vec_error);
-        let x1 = rgbv_error.first().unwrap();
-        p.0 = x1.2;
+    for y in 0..height {
+        // c64 multi color mode only has an independent pixel every second column.
+        let left_to_right = !serpentine || y % 2 == 0;
+        let xs: Vec<u32> = if left_to_right {
+            (0..width).step_by(2).collect()
+        } else {
+            (0..width).step_by(2).rev().collect()
+        };
 
-        // accumulate the error. XXX SKerkewitz: this is actually wrong, but looks fine
-        lum_acc_vec_error = vec3_scale(vec3_add(lum_acc_vec_error, x1.1), 0.5);
-    });
+        for x in xs {
+            let index = (y * width + x) as usize;
+            let accumulated = errors[index];
+
+            let rgbv_error = rgbv_error_table(palette, delta_e_mode, rgb_to_vec3f(image.get_pixel(x, y).0), accumulated);
+            let best = rgbv_error.first().unwrap();
+            image.get_pixel_mut(x, y).0 = best.2;
+
+            diffuse_error(&mut errors, width, height, x, y, best.1, kernel, left_to_right);
+        }
+    }
 }
 
 fn c64_multicolor_pixel_fix(image: &mut RgbImage) {
@@ -221,16 +488,406 @@ fn stripe_effect(image: &mut RgbImage) {
     }
 }
 
-fn convert_image(in_name: &str, out_name: &str) -> Result<(), Box<dyn Error>> {
+// Koala Painter (.koa) file layout: 2 byte load address, 8000 byte bitmap,
+// 1000 byte screen RAM, 1000 byte color RAM, 1 background byte.
+const KOALA_LOAD_ADDRESS: [u8; 2] = [0x00, 0x60];
+
+/// Look up a color's index within `palette`.
+fn c64_color_index(palette: &Palette, rgb: RGBColor3b) -> u8 {
+    palette.entries.iter().position(|(_, c)| *c == rgb).unwrap_or(0) as u8
+}
+
+/// Pick the color that appears most often across the whole image. Koala
+/// files only store a single shared background, so every cell needs to
+/// agree on the same one.
+fn c64_pick_background(image: &RgbImage) -> RGBColor3b {
+    let mut counts: HashMap<RGBColor3b, u32> = HashMap::new();
+    for p in image.pixels() {
+        *counts.entry(p.0).or_insert(0) += 1;
+    }
+    counts.into_iter().max_by_key(|(_, count)| *count).map(|(c, _)| c).unwrap_or(C64_BLACK)
+}
+
+fn rgb_distance_sq(a: RGBColor3b, b: RGBColor3b) -> i32 {
+    let dr = a[0] as i32 - b[0] as i32;
+    let dg = a[1] as i32 - b[1] as i32;
+    let db = a[2] as i32 - b[2] as i32;
+    dr * dr + dg * dg + db * db
+}
+
+/// Encode a 320x200 pixel buffer (40x25 cells of 8x8 pixels, each pixel
+/// column-doubled by `c64_multicolor_pixel_fix` into the 160x200 multicolor
+/// resolution the C64 actually displays) as a
+/// Koala Painter (.koa) file so it can be loaded on hardware/emulators.
+fn write_koala(palette: &Palette, image: &RgbImage, out_name: &str) -> io::Result<()> {
+    let background = c64_pick_background(image);
+
+    let mut bitmap = vec![0u8; 8000];
+    let mut screen_ram = vec![0u8; 1000];
+    let mut color_ram = vec![0u8; 1000];
+
+    for cell_y in 0..25u32 {
+        for cell_x in 0..40u32 {
+            let offset_x = cell_x * 8;
+            let offset_y = cell_y * 8;
+
+            // Count the distinct colors used in this cell, background excluded:
+            // the background always claims the "00" slot whether or not this
+            // cell actually contains it.
+            let mut counts: HashMap<RGBColor3b, u32> = HashMap::new();
+            for y in offset_y..(offset_y + 8) {
+                for x in offset_x..(offset_x + 8) {
+                    let c = image.get_pixel(x, y).0;
+                    if c != background {
+                        *counts.entry(c).or_insert(0) += 1;
+                    }
+                }
+            }
+
+            // A cell only has three non-background slots (01, 10, 11). Keep the
+            // three most common colors and remap any overflow color onto its
+            // nearest kept neighbor, rather than silently dropping pixel data.
+            let mut by_count: Vec<(RGBColor3b, u32)> = counts.into_iter().collect();
+            by_count.sort_by(|a, b| b.1.cmp(&a.1));
+            let kept: Vec<RGBColor3b> = by_count.iter().take(3).map(|(c, _)| *c).collect();
+
+            let fg1 = *kept.get(0).unwrap_or(&background);
+            let fg2 = *kept.get(1).unwrap_or(&background);
+            let fg3 = *kept.get(2).unwrap_or(&background);
+
+            let remap = |c: RGBColor3b| -> RGBColor3b {
+                if c == background || kept.contains(&c) {
+                    c
+                } else {
+                    *kept.iter().min_by_key(|k| rgb_distance_sq(c, **k)).unwrap_or(&background)
+                }
+            };
+
+            let cell_index = (cell_y * 40 + cell_x) as usize;
+            screen_ram[cell_index] = (c64_color_index(palette, fg1) << 4) | c64_color_index(palette, fg2);
+            color_ram[cell_index] = c64_color_index(palette, fg3);
+
+            for row in 0..8u32 {
+                let y = offset_y + row;
+                let mut byte = 0u8;
+                for pair in 0..4u32 {
+                    let x = offset_x + pair * 2;
+                    let pixel = remap(image.get_pixel(x, y).0);
+                    let code: u8 = if pixel == background {
+                        0b00
+                    } else if pixel == fg1 {
+                        0b01
+                    } else if pixel == fg2 {
+                        0b10
+                    } else if pixel == fg3 {
+                        0b11
+                    } else {
+                        0b00
+                    };
+                    byte |= code << ((3 - pair) * 2);
+                }
+                bitmap[cell_index * 8 + row as usize] = byte;
+            }
+        }
+    }
+
+    let mut out = fs::File::create(out_name)?;
+    out.write_all(&KOALA_LOAD_ADDRESS)?;
+    out.write_all(&bitmap)?;
+    out.write_all(&screen_ram)?;
+    out.write_all(&color_ram)?;
+    out.write_all(&[c64_color_index(palette, background)])?;
+    Ok(())
+}
+
+// Screen codes 0..=63 of the C64's uppercase/graphics charset: '@' (0),
+// A-Z (1..=26), punctuation ([, pound, ], up-arrow, left-arrow at 27..=31),
+// then space through '?' (32..=63), which share the ASCII 0x20..=0x3F
+// glyphs. These are real, distinguishable glyph bitmaps (not a byte-exact
+// ROM dump -- swap this table for a dumped character ROM if hardware-exact
+// PETSCII output is required).
+const C64_CHARSET_BASE: [[u8; 8]; 64] = [
+    // 0: @
+    [0b00111100, 0b01000010, 0b10011001, 0b10100101, 0b10100101, 0b10011110, 0b01000000, 0b00111110],
+    // 1-26: A-Z
+    [0b00011000, 0b00100100, 0b01000010, 0b01000010, 0b01111110, 0b01000010, 0b01000010, 0b00000000], // A
+    [0b01111100, 0b01000010, 0b01000010, 0b01111100, 0b01000010, 0b01000010, 0b01111100, 0b00000000], // B
+    [0b00111100, 0b01000010, 0b01000000, 0b01000000, 0b01000000, 0b01000010, 0b00111100, 0b00000000], // C
+    [0b01111000, 0b01000100, 0b01000010, 0b01000010, 0b01000010, 0b01000100, 0b01111000, 0b00000000], // D
+    [0b01111110, 0b01000000, 0b01000000, 0b01111100, 0b01000000, 0b01000000, 0b01111110, 0b00000000], // E
+    [0b01111110, 0b01000000, 0b01000000, 0b01111100, 0b01000000, 0b01000000, 0b01000000, 0b00000000], // F
+    [0b00111100, 0b01000010, 0b01000000, 0b01001110, 0b01000010, 0b01000010, 0b00111110, 0b00000000], // G
+    [0b01000010, 0b01000010, 0b01000010, 0b01111110, 0b01000010, 0b01000010, 0b01000010, 0b00000000], // H
+    [0b00111100, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00111100, 0b00000000], // I
+    [0b00011110, 0b00001100, 0b00001100, 0b00001100, 0b00001100, 0b01001100, 0b00111000, 0b00000000], // J
+    [0b01000100, 0b01001000, 0b01010000, 0b01100000, 0b01010000, 0b01001000, 0b01000100, 0b00000000], // K
+    [0b01000000, 0b01000000, 0b01000000, 0b01000000, 0b01000000, 0b01000000, 0b01111110, 0b00000000], // L
+    [0b01000010, 0b01100110, 0b01011010, 0b01000010, 0b01000010, 0b01000010, 0b01000010, 0b00000000], // M
+    [0b01000010, 0b01100010, 0b01010010, 0b01001010, 0b01000110, 0b01000010, 0b01000010, 0b00000000], // N
+    [0b00111100, 0b01000010, 0b01000010, 0b01000010, 0b01000010, 0b01000010, 0b00111100, 0b00000000], // O
+    [0b01111100, 0b01000010, 0b01000010, 0b01111100, 0b01000000, 0b01000000, 0b01000000, 0b00000000], // P
+    [0b00111100, 0b01000010, 0b01000010, 0b01000010, 0b01001010, 0b01000100, 0b00111010, 0b00000000], // Q
+    [0b01111100, 0b01000010, 0b01000010, 0b01111100, 0b01010000, 0b01001000, 0b01000100, 0b00000000], // R
+    [0b00111100, 0b01000010, 0b01000000, 0b00111100, 0b00000010, 0b01000010, 0b00111100, 0b00000000], // S
+    [0b01111110, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00000000], // T
+    [0b01000010, 0b01000010, 0b01000010, 0b01000010, 0b01000010, 0b01000010, 0b00111100, 0b00000000], // U
+    [0b01000010, 0b01000010, 0b01000010, 0b01000010, 0b01000010, 0b00100100, 0b00011000, 0b00000000], // V
+    [0b01000010, 0b01000010, 0b01000010, 0b01000010, 0b01011010, 0b01100110, 0b01000010, 0b00000000], // W
+    [0b01000010, 0b00100100, 0b00011000, 0b00011000, 0b00011000, 0b00100100, 0b01000010, 0b00000000], // X
+    [0b01000010, 0b00100100, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00000000], // Y
+    [0b01111110, 0b00000100, 0b00001000, 0b00010000, 0b00100000, 0b01000000, 0b01111110, 0b00000000], // Z
+    // 27-31: [, pound, ], up-arrow, left-arrow
+    [0b00111100, 0b00110000, 0b00110000, 0b00110000, 0b00110000, 0b00110000, 0b00111100, 0b00000000], // [
+    [0b00011100, 0b00100010, 0b00100000, 0b01111000, 0b00100000, 0b00100010, 0b01111110, 0b00000000], // pound
+    [0b00111100, 0b00001100, 0b00001100, 0b00001100, 0b00001100, 0b00001100, 0b00111100, 0b00000000], // ]
+    [0b00011000, 0b00111100, 0b01111110, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00000000], // up-arrow
+    [0b00001000, 0b00011000, 0b00111111, 0b01111111, 0b00111111, 0b00011000, 0b00001000, 0b00000000], // left-arrow
+    // 32-63: space, !"#$%&'()*+,-./0123456789:;<=>?
+    [0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000], // space
+    [0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b00000000, 0b00011000, 0b00000000], // !
+    [0b01100110, 0b01100110, 0b01100110, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000], // "
+    [0b00100100, 0b00100100, 0b01111110, 0b00100100, 0b01111110, 0b00100100, 0b00100100, 0b00000000], // #
+    [0b00011000, 0b00111110, 0b01100000, 0b00111100, 0b00000110, 0b01111100, 0b00011000, 0b00000000], // $
+    [0b01100010, 0b01100100, 0b00001000, 0b00010000, 0b00100000, 0b01000110, 0b01000010, 0b00000000], // %
+    [0b00111000, 0b01000100, 0b01001000, 0b00110000, 0b01001010, 0b01000100, 0b00111010, 0b00000000], // &
+    [0b00011000, 0b00011000, 0b00110000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000], // '
+    [0b00001100, 0b00011000, 0b00110000, 0b00110000, 0b00110000, 0b00011000, 0b00001100, 0b00000000], // (
+    [0b00110000, 0b00011000, 0b00001100, 0b00001100, 0b00001100, 0b00011000, 0b00110000, 0b00000000], // )
+    [0b00000000, 0b01000010, 0b00100100, 0b00011000, 0b00100100, 0b01000010, 0b00000000, 0b00000000], // *
+    [0b00000000, 0b00011000, 0b00011000, 0b01111110, 0b00011000, 0b00011000, 0b00000000, 0b00000000], // +
+    [0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00011000, 0b00011000, 0b00110000], // ,
+    [0b00000000, 0b00000000, 0b00000000, 0b01111110, 0b00000000, 0b00000000, 0b00000000, 0b00000000], // -
+    [0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00000000, 0b00011000, 0b00011000, 0b00000000], // .
+    [0b00000010, 0b00000100, 0b00001000, 0b00010000, 0b00100000, 0b01000000, 0b10000000, 0b00000000], // /
+    [0b00111100, 0b01000110, 0b01001010, 0b01010010, 0b01100010, 0b01000010, 0b00111100, 0b00000000], // 0
+    [0b00011000, 0b00111000, 0b00011000, 0b00011000, 0b00011000, 0b00011000, 0b01111110, 0b00000000], // 1
+    [0b00111100, 0b01000010, 0b00000010, 0b00001100, 0b00110000, 0b01000000, 0b01111110, 0b00000000], // 2
+    [0b01111100, 0b00000010, 0b00000010, 0b00111100, 0b00000010, 0b00000010, 0b01111100, 0b00000000], // 3
+    [0b00000100, 0b00001100, 0b00010100, 0b00100100, 0b01111110, 0b00000100, 0b00000100, 0b00000000], // 4
+    [0b01111110, 0b01000000, 0b01111100, 0b00000010, 0b00000010, 0b01000010, 0b00111100, 0b00000000], // 5
+    [0b00011100, 0b00100000, 0b01000000, 0b01111100, 0b01000010, 0b01000010, 0b00111100, 0b00000000], // 6
+    [0b01111110, 0b00000010, 0b00000100, 0b00001000, 0b00010000, 0b00010000, 0b00010000, 0b00000000], // 7
+    [0b00111100, 0b01000010, 0b01000010, 0b00111100, 0b01000010, 0b01000010, 0b00111100, 0b00000000], // 8
+    [0b00111100, 0b01000010, 0b01000010, 0b00111110, 0b00000010, 0b00000100, 0b00111000, 0b00000000], // 9
+    [0b00000000, 0b00011000, 0b00011000, 0b00000000, 0b00011000, 0b00011000, 0b00000000, 0b00000000], // :
+    [0b00000000, 0b00011000, 0b00011000, 0b00000000, 0b00011000, 0b00011000, 0b00110000, 0b00000000], // ;
+    [0b00000110, 0b00011000, 0b01100000, 0b10000000, 0b01100000, 0b00011000, 0b00000110, 0b00000000], // <
+    [0b00000000, 0b00000000, 0b01111110, 0b00000000, 0b01111110, 0b00000000, 0b00000000, 0b00000000], // =
+    [0b01100000, 0b00011000, 0b00000110, 0b00000001, 0b00000110, 0b00011000, 0b01100000, 0b00000000], // >
+    [0b00111100, 0b01000010, 0b00000010, 0b00001100, 0b00011000, 0b00000000, 0b00011000, 0b00000000], // ?
+];
+
+/// Codes 64..=127 are the C64's graphics/box-drawing half of the charset,
+/// which has no ASCII equivalent; synthesize distinct filler block patterns
+/// for that range rather than claiming a specific graphic design. Codes
+/// 128..=255 are the bitwise inverse of 0..=127, matching PETSCII's
+/// reverse-field half.
+const fn build_c64_charset() -> [[u8; 8]; 256] {
+    let mut charset = [[0u8; 8]; 256];
+
+    let mut i = 0;
+    while i < 64 {
+        charset[i] = C64_CHARSET_BASE[i];
+        i += 1;
+    }
+
+    while i < 128 {
+        let k = (i - 64) as u8;
+        let mut glyph = [0u8; 8];
+        let mut row = 0;
+        while row < 8 {
+            glyph[row] = k.wrapping_mul(37).wrapping_add((row as u8) * 11 + 1);
+            row += 1;
+        }
+        charset[i] = glyph;
+        i += 1;
+    }
+
+    while i < 256 {
+        let base = charset[i - 128];
+        let mut inverted = [0u8; 8];
+        let mut row = 0;
+        while row < 8 {
+            inverted[row] = !base[row];
+            row += 1;
+        }
+        charset[i] = inverted;
+        i += 1;
+    }
+
+    charset
+}
+
+const C64_CHARSET: [[u8; 8]; 256] = build_c64_charset();
+
+fn hamming_distance(a: &[u8; 8], b: &[u8; 8]) -> u32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
+
+/// Reduce an 8x8 region to its two dominant colors, foreground (most common)
+/// first, background second.
+fn c64_two_color_fit(image: &RgbImage, offset_x: u32, offset_y: u32) -> (RGBColor3b, RGBColor3b) {
+    let mut counts: HashMap<RGBColor3b, u32> = HashMap::new();
+    for y in offset_y..(offset_y + 8) {
+        for x in offset_x..(offset_x + 8) {
+            *counts.entry(image.get_pixel(x, y).0).or_insert(0) += 1;
+        }
+    }
+
+    let mut by_count: Vec<(RGBColor3b, u32)> = counts.into_iter().collect();
+    by_count.sort_by(|a, b| b.1.cmp(&a.1));
+
+    let fg = by_count.get(0).map(|c| c.0).unwrap_or(C64_BLACK);
+    let bg = by_count.get(1).map(|c| c.0).unwrap_or(fg);
+    (fg, bg)
+}
+
+/// Threshold an 8x8 region into a 64-bit mask (one bit per pixel, 1 = `fg`).
+fn c64_threshold_mask(image: &RgbImage, offset_x: u32, offset_y: u32, fg: RGBColor3b) -> [u8; 8] {
+    let mut mask = [0u8; 8];
+    for row in 0..8u32 {
+        let y = offset_y + row;
+        let mut byte = 0u8;
+        for col in 0..8u32 {
+            let x = offset_x + col;
+            if image.get_pixel(x, y).0 == fg {
+                byte |= 0x80 >> col;
+            }
+        }
+        mask[row as usize] = byte;
+    }
+    mask
+}
+
+/// Score a thresholded mask against every glyph in `C64_CHARSET` (and its
+/// inverse, for PETSCII's reverse-field characters), returning the best
+/// match and whether it should be drawn inverted.
+fn c64_best_glyph(mask: &[u8; 8]) -> (u8, bool) {
+    let mut best_index = 0u8;
+    let mut best_inverted = false;
+    let mut best_distance = u32::MAX;
+
+    for (index, glyph) in C64_CHARSET.iter().enumerate() {
+        let direct = hamming_distance(mask, glyph);
+        if direct < best_distance {
+            best_distance = direct;
+            best_index = index as u8;
+            best_inverted = false;
+        }
+
+        let mut inverted_glyph = [0u8; 8];
+        for i in 0..8 {
+            inverted_glyph[i] = !glyph[i];
+        }
+        let inverted = hamming_distance(mask, &inverted_glyph);
+        if inverted < best_distance {
+            best_distance = inverted;
+            best_index = index as u8;
+            best_inverted = true;
+        }
+    }
+
+    (best_index, best_inverted)
+}
+
+// The C64 text screen is a fixed 40x25 grid of 8x8 character cells.
+const PETSCII_COLS: u32 = 40;
+const PETSCII_ROWS: u32 = 25;
+// Screen code 32 is a blank space, used to pad cells beyond the source image.
+const PETSCII_BLANK_GLYPH: u8 = 32;
+
+/// Convert an image into C64 text mode: every 8x8 block is matched against
+/// `C64_CHARSET` and reduced to a screen code plus a color RAM entry,
+/// producing a fixed 1000 byte screen RAM and 1000 byte color RAM array
+/// (the C64's 40x25 text grid) ready to poke into $0400/$d800. Images larger
+/// than 320x200 are cropped to the top-left 40x25 cells; images smaller than
+/// that are padded with blank cells.
+fn write_petscii(palette: &Palette, image: &RgbImage, out_name: &str) -> io::Result<()> {
+    let cols = (image.width() / 8).min(PETSCII_COLS);
+    let rows = (image.height() / 8).min(PETSCII_ROWS);
+
+    let mut screen_ram = vec![PETSCII_BLANK_GLYPH; (PETSCII_COLS * PETSCII_ROWS) as usize];
+    let mut color_ram = vec![0u8; (PETSCII_COLS * PETSCII_ROWS) as usize];
+
+    for row in 0..rows {
+        for col in 0..cols {
+            let offset_x = col * 8;
+            let offset_y = row * 8;
+
+            let (fg, bg) = c64_two_color_fit(image, offset_x, offset_y);
+            let mask = c64_threshold_mask(image, offset_x, offset_y, fg);
+            let (glyph, inverted) = c64_best_glyph(&mask);
+
+            let cell = (row * PETSCII_COLS + col) as usize;
+            screen_ram[cell] = glyph;
+            color_ram[cell] = c64_color_index(palette, if inverted { bg } else { fg });
+        }
+    }
+
+    let mut out = fs::File::create(out_name)?;
+    out.write_all(&screen_ram)?;
+    out.write_all(&color_ram)?;
+    Ok(())
+}
 
-    let mut dynamic_image = ImageReader::open(in_name)?.decode()?;
+/// Dither every frame of an animated GIF in parallel and re-encode it as an
+/// animated GIF, preserving each frame's position/delay.
+fn convert_animated_gif(palette: &Palette, delta_e_mode: DeltaE, kernel: &DiffusionKernel, serpentine: bool, in_name: &str, out_name: &str) -> Result<String, Box<dyn Error>> {
+    let decoder = GifDecoder::new(fs::File::open(in_name)?)?;
+    let frames = decoder.into_frames().collect_frames()?;
+
+    let dithered_frames: Vec<image::Frame> = frames.into_par_iter().map(|frame| {
+        let delay = frame.delay();
+        let left = frame.left();
+        let top = frame.top();
+
+        let mut rgb_image = DynamicImage::ImageRgba8(frame.into_buffer()).to_rgb8();
+        c64_dither(palette, delta_e_mode, &mut rgb_image, kernel, serpentine);
+        c64_multicolor_pixel_fix(&mut rgb_image);
+        c64_reduce_color_per_block(&mut rgb_image);
+
+        image::Frame::from_parts(DynamicImage::ImageRgb8(rgb_image).to_rgba8(), left, top, delay)
+    }).collect();
+
+    let gif_name = out_name.replace(".png", ".gif");
+    let mut out = fs::File::create(&gif_name)?;
+    let mut encoder = GifEncoder::new(&mut out);
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(dithered_frames.into_iter())?;
+    Ok(gif_name)
+}
+
+/// Dither `in_name` and write the result, returning the path that was
+/// actually written. GIF inputs are routed to `convert_animated_gif` and
+/// produce a `.gif`; everything else produces a `.png` at `out_name` (plus
+/// any `.koa`/`.scr` side-outputs, which are not reflected in the return
+/// value since `out_name` still names the primary artifact).
+fn convert_image(palette: &Palette, delta_e_mode: DeltaE, kernel: &DiffusionKernel, serpentine: bool, in_name: &str, out_name: &str) -> Result<String, Box<dyn Error>> {
+
+    let reader = ImageReader::open(in_name)?.with_guessed_format()?;
+    if reader.format() == Some(ImageFormat::Gif) {
+        return convert_animated_gif(palette, delta_e_mode, kernel, serpentine, in_name, out_name);
+    }
+
+    let mut dynamic_image = reader.decode()?;
     let mut rgb_image = dynamic_image.as_mut_rgb8().ok_or(SimpleError::new("Could not get mut rgb8"))?;
-    c64_dither(rgb_image);
+    c64_dither(palette, delta_e_mode, rgb_image, kernel, serpentine);
     c64_multicolor_pixel_fix(rgb_image);
     c64_reduce_color_per_block(rgb_image);
 
     rgb_image.save(out_name)?;
-    Ok(())
+
+    if rgb_image.width() == 320 && rgb_image.height() == 200 {
+        let koala_name = out_name.replace(".png", ".koa");
+        write_koala(palette, rgb_image, koala_name.as_str())?;
+    }
+
+    if rgb_image.width() % 8 == 0 && rgb_image.height() % 8 == 0 {
+        let petscii_name = out_name.replace(".png", ".scr");
+        write_petscii(palette, rgb_image, petscii_name.as_str())?;
+    }
+
+    Ok(out_name.to_string())
 }
 
 fn fix_pixel_block(block: &mut Vec<(usize, [u8; 3], Vec<(u32, u32)>)>, rgb: &mut RgbImage) {
@@ -250,6 +907,15 @@ fn fix_pixel_block(block: &mut Vec<(usize, [u8; 3], Vec<(u32, u32)>)>, rgb: &mut
     }
 }
 
+/// Detect a decodable image by content (magic bytes), not file extension, so
+/// PNG/GIF/TIFF/WebP/BMP inputs are picked up alongside JPEG.
+fn is_decodable_image(path: &Path) -> bool {
+    ImageReader::open(path)
+        .and_then(|r| r.with_guessed_format())
+        .map(|r| r.format().is_some())
+        .unwrap_or(false)
+}
+
 fn list_files(input_dir: &Path) -> io::Result<Vec<DirEntry>> {
 
     let result = fs::read_dir(input_dir)?
@@ -265,14 +931,14 @@ fn list_files(input_dir: &Path) -> io::Result<Vec<DirEntry>> {
         })
         .flatten()
         .flatten()
-        .filter(|f|f.file_name().to_str().unwrap().ends_with("jpg"))
+        .filter(|f| is_decodable_image(&f.path()))
         .collect();
 
     Ok(result)
 }
 
 
-fn dither_single_file(source_path: &Path, out_dir: &Path) {
+fn dither_single_file(palette: &Palette, delta_e_mode: DeltaE, kernel: &DiffusionKernel, serpentine: bool, source_path: &Path, out_dir: &Path) {
     let out_file_name = if out_dir.is_dir() {
         let source_file_name = source_path.file_name().unwrap();
         out_dir.join(source_file_name).to_path_buf()
@@ -281,15 +947,15 @@ fn dither_single_file(source_path: &Path, out_dir: &Path) {
     };
 
     fs::create_dir_all(&out_file_name.parent().unwrap()).unwrap();
-    let out_name = out_file_name.to_str().unwrap().replace(".jpg", ".png");
+    let out_name = out_file_name.with_extension("png").to_str().unwrap().to_string();
     let input_file = source_path.to_str().unwrap();
-    match convert_image(input_file, out_name.as_str()) {
-        Ok(_) => println!("Did convert '{}' to '{}'...", input_file, out_name),
+    match convert_image(palette, delta_e_mode, kernel, serpentine, input_file, out_name.as_str()) {
+        Ok(written_name) => println!("Did convert '{}' to '{}'...", input_file, written_name),
         Err(e) => eprintln!("Failed to convert '{}' to '{}' because of {}", input_file, out_name, e),
     }
 }
 
-fn dither_folder_recursive(source_path: &Path, out_dir: &Path) {
+fn dither_folder_recursive(palette: &Palette, delta_e_mode: DeltaE, kernel: &DiffusionKernel, serpentine: bool, source_path: &Path, out_dir: &Path) {
     let vec = list_files(source_path).unwrap();
     vec.into_par_iter().for_each(|dir_entry| {
         let p = dir_entry.path();
@@ -300,32 +966,145 @@ fn dither_folder_recursive(source_path: &Path, out_dir: &Path) {
         fs::create_dir_all(&out_path.parent().unwrap()).unwrap();
 
         let input_file = p.to_str().unwrap();
-        let output_file = out_path.to_str().unwrap();
-
-        let out_name = output_file.replace(".jpg", ".png");
-        match convert_image(input_file, out_name.as_str()) {
-            Ok(_) => println!("Did convert '{}' to '{}'...", input_file, out_name),
+        let out_name = out_path.with_extension("png").to_str().unwrap().to_string();
+        match convert_image(palette, delta_e_mode, kernel, serpentine, input_file, out_name.as_str()) {
+            Ok(written_name) => println!("Did convert '{}' to '{}'...", input_file, written_name),
             Err(e) => eprintln!("Failed to convert '{}' to '{}' because of {}", input_file, out_name, e),
         }
     });
 }
 
-fn main() {
+struct CliArgs {
+    palette: Palette,
+    delta_e_mode: DeltaE,
+    kernel: &'static DiffusionKernel,
+    serpentine: bool,
+    source: String,
+    out_dir: String,
+}
+
+/// Parse `[--palette <name|file>] [--kernel <name>] [--serpentine] [--delta-e <name>] <input dir or file> <output dir>`.
+fn parse_args(args: &[String]) -> Result<CliArgs, Box<dyn Error>> {
+    let mut palette_name: Option<String> = None;
+    let mut kernel_name: Option<String> = None;
+    let mut delta_e_name: Option<String> = None;
+    let mut serpentine = false;
+    let mut positional: Vec<String> = Vec::new();
+
+    let mut i = 1;
+    while i < args.len() {
+        let a = &args[i];
+        if let Some(value) = a.strip_prefix("--palette=") {
+            palette_name = Some(value.to_string());
+        } else if a == "--palette" {
+            i += 1;
+            let value = args.get(i).ok_or_else(|| SimpleError::new("--palette needs a value"))?;
+            palette_name = Some(value.clone());
+        } else if let Some(value) = a.strip_prefix("--kernel=") {
+            kernel_name = Some(value.to_string());
+        } else if a == "--kernel" {
+            i += 1;
+            let value = args.get(i).ok_or_else(|| SimpleError::new("--kernel needs a value"))?;
+            kernel_name = Some(value.clone());
+        } else if let Some(value) = a.strip_prefix("--delta-e=") {
+            delta_e_name = Some(value.to_string());
+        } else if a == "--delta-e" {
+            i += 1;
+            let value = args.get(i).ok_or_else(|| SimpleError::new("--delta-e needs a value"))?;
+            delta_e_name = Some(value.clone());
+        } else if a == "--serpentine" {
+            serpentine = true;
+        } else {
+            positional.push(a.clone());
+        }
+        i += 1;
+    }
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        println!("{} <input dir or file> <output dir>", args[0]);
-        exit(0);
+    if positional.len() < 2 {
+        return Err(Box::new(SimpleError::new("not enough arguments")));
     }
 
-    let source_path = Path::new(args[1].as_str());
-    let out_dir = Path::new(args[2].as_str());
+    let palette = match palette_name {
+        Some(name) => Palette::load(name.as_str())?,
+        None => Palette::from_colors(&C64_PALETTE_PEPTO),
+    };
+
+    let kernel = match kernel_name {
+        Some(name) => diffusion_kernel_named(name.as_str())
+            .ok_or_else(|| SimpleError::new(format!("unknown diffusion kernel '{}'", name)))?,
+        None => &KERNEL_FLOYD_STEINBERG,
+    };
+
+    let delta_e_mode = match delta_e_name {
+        Some(name) => delta_e_named(name.as_str())
+            .ok_or_else(|| SimpleError::new(format!("unknown delta-e metric '{}'", name)))?,
+        None => DeltaE::Euclidean,
+    };
+
+    Ok(CliArgs { palette, delta_e_mode, kernel, serpentine, source: positional[0].clone(), out_dir: positional[1].clone() })
+}
+
+fn main() {
+
+    let raw_args: Vec<String> = env::args().collect();
+    let args = match parse_args(&raw_args) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}", e);
+            println!("{} [--palette <name|file>] [--kernel <name>] [--serpentine] [--delta-e <name>] <input dir or file> <output dir>", raw_args[0]);
+            exit(0);
+        }
+    };
+
+    let source_path = Path::new(args.source.as_str());
+    let out_dir = Path::new(args.out_dir.as_str());
 
     if source_path.is_file() {
-        dither_single_file(source_path, out_dir);
+        dither_single_file(&args.palette, args.delta_e_mode, args.kernel, args.serpentine, source_path, out_dir);
     } else if source_path.is_dir() {
-        dither_folder_recursive(source_path, out_dir);
+        dither_folder_recursive(&args.palette, args.delta_e_mode, args.kernel, args.serpentine, source_path, out_dir);
     } else {
         eprintln!("Given source '{}' is neither file or directory", source_path.to_str().unwrap());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard(width: u32, height: u32) -> RgbImage {
+        ImageBuffer::from_fn(width, height, |x, y| {
+            image::Rgb(if (x / 8 + y / 8) % 2 == 0 { C64_WHITE } else { C64_RED })
+        })
+    }
+
+    #[test]
+    fn write_koala_accepts_a_real_320x200_buffer() {
+        let palette = Palette::from_colors(&C64_PALETTE_PEPTO);
+        let image = checkerboard(320, 200);
+        let out_name = std::env::temp_dir().join("write_koala_320x200_test.koa");
+        write_koala(&palette, &image, out_name.to_str().unwrap()).unwrap();
+
+        let written = fs::read(&out_name).unwrap();
+        // 2 byte load address + 8000 byte bitmap + 1000 byte screen RAM +
+        // 1000 byte color RAM + 1 background byte.
+        assert_eq!(written.len(), 2 + 8000 + 1000 + 1000 + 1);
+
+        fs::remove_file(&out_name).ok();
+    }
+
+    #[test]
+    fn write_petscii_pads_a_smaller_than_320x200_buffer() {
+        let palette = Palette::from_colors(&C64_PALETTE_PEPTO);
+        let image = checkerboard(160, 200);
+        let out_name = std::env::temp_dir().join("write_petscii_160x200_test.scr");
+        write_petscii(&palette, &image, out_name.to_str().unwrap()).unwrap();
+
+        let written = fs::read(&out_name).unwrap();
+        // Always a fixed 40x25 screen RAM + 40x25 color RAM, regardless of
+        // the source image's actual dimensions.
+        assert_eq!(written.len(), 1000 + 1000);
+
+        fs::remove_file(&out_name).ok();
+    }
+}